@@ -1,16 +1,20 @@
 #![cfg_attr(not(feature="unstable"), allow(dead_code))]
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::fmt;
+use std::future::Future;
 use std::str;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 
-use async_std::prelude::StreamExt;
-use async_std::future::{timeout, pending};
-use async_std::io::prelude::WriteExt;
-use async_std::io::ReadExt;
+use async_std::sync::Mutex;
 use bytes::{Bytes, BytesMut};
+use futures_util::channel::mpsc;
+use futures_util::future::BoxFuture;
+use futures_util::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use futures_util::io::{ReadHalf, WriteHalf};
+use futures_util::StreamExt;
 use typemap::TypeMap;
 use tls_api::TlsStream;
 
@@ -19,13 +23,13 @@ use edgedb_protocol::client_message::ClientMessage;
 use edgedb_protocol::client_message::{DescribeStatement, DescribeAspect};
 use edgedb_protocol::client_message::{Execute, ExecuteScript};
 use edgedb_protocol::client_message::{Prepare, IoFormat, Cardinality};
-use edgedb_protocol::descriptors::OutputTypedesc;
+use edgedb_protocol::descriptors::{OutputTypedesc, InputTypedesc};
 use edgedb_protocol::encoding::Output;
 use edgedb_protocol::features::ProtocolVersion;
 use edgedb_protocol::query_arg::{QueryArgs, Encoder};
 use edgedb_protocol::queryable::{Queryable};
 use edgedb_protocol::server_message::ServerMessage;
-use edgedb_protocol::server_message::{TransactionState};
+use edgedb_protocol::server_message::{ErrorResponse, TransactionState};
 
 use crate::debug::PartialDebug;
 use crate::errors::{ClientConnectionError, ProtocolError};
@@ -34,32 +38,321 @@ use crate::errors::{ClientInconsistentError, ClientEncodingError};
 use crate::errors::{Error, ErrorKind, ResultExt};
 use crate::errors::{NoResultExpected, NoDataError};
 use crate::errors::{ProtocolOutOfOrderError, ProtocolEncodingError};
+use crate::errors::TransactionConflictError;
 use crate::reader::{self, QueryResponse, Reader};
 use crate::server_params::ServerParam;
 
 
+/// Maximum number of attempts made by [`Connection::transaction`] before
+/// giving up and returning the last error to the caller.
+const DEFAULT_MAX_TRANSACTION_ATTEMPTS: u32 = 10;
+
+/// Suggested `window` argument for [`Connection::execute_stream`]: the
+/// number of un-acknowledged `Execute` messages kept outstanding at once.
+/// It is not applied automatically; callers pass it explicitly.
+pub const DEFAULT_STREAM_WINDOW: usize = 16;
+
+/// Key identifying a cached prepared statement: the query text plus the
+/// output shape parameters it was prepared with.
+type StatementKey = (String, IoFormat, Cardinality);
+
+#[derive(Debug, Clone)]
+pub(crate) struct CachedStatement {
+    pub(crate) name: Bytes,
+    pub(crate) output: OutputTypedesc,
+    pub(crate) input: InputTypedesc,
+}
+
+/// Recency order for a fixed-capacity cache, kept independent of the
+/// value type it's keyed to so the eviction policy can be unit tested
+/// without needing a real cached value on hand. `capacity == 0` means
+/// unbounded.
+#[derive(Debug)]
+struct LruOrder<K> {
+    capacity: usize,
+    order: VecDeque<K>,
+}
+
+impl<K: Eq + Clone> LruOrder<K> {
+    fn new(capacity: usize) -> LruOrder<K> {
+        LruOrder { capacity, order: VecDeque::new() }
+    }
+
+    /// Mark `key` as the most recently used.
+    fn touch(&mut self, key: &K) {
+        if let Some(pos) = self.order.iter().position(|k| k == key) {
+            let k = self.order.remove(pos).unwrap();
+            self.order.push_back(k);
+        }
+    }
+
+    /// Record a fresh insert of `key`, returning the key evicted to make
+    /// room for it, if any.
+    fn insert(&mut self, key: K) -> Option<K> {
+        if let Some(pos) = self.order.iter().position(|k| k == &key) {
+            self.order.remove(pos);
+            self.order.push_back(key);
+            return None;
+        }
+        let evicted = if self.capacity > 0 && self.order.len() >= self.capacity {
+            self.order.pop_front()
+        } else {
+            None
+        };
+        self.order.push_back(key);
+        evicted
+    }
+
+    fn remove(&mut self, key: &K) {
+        if let Some(pos) = self.order.iter().position(|k| k == key) {
+            self.order.remove(pos);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.order.clear();
+    }
+
+    /// Change the capacity, evicting the least-recently-used keys (oldest
+    /// first) until the order is no longer over the new capacity, and
+    /// returning whatever got evicted so the caller can drop them from
+    /// its own value map.
+    fn set_capacity(&mut self, capacity: usize) -> Vec<K> {
+        self.capacity = capacity;
+        let mut evicted = Vec::new();
+        while self.capacity > 0 && self.order.len() > self.capacity {
+            match self.order.pop_front() {
+                Some(key) => evicted.push(key),
+                None => break,
+            }
+        }
+        evicted
+    }
+}
+
+/// A small LRU cache of server-side prepared statements, keyed by the
+/// query text and the `IoFormat`/`Cardinality` it was prepared with.
+///
+/// This lets [`Sequence::_query`] skip the `Prepare` + `DescribeStatement`
+/// round-trip on repeated queries and jump straight to `Execute`.
+#[derive(Debug)]
+pub(crate) struct StatementCache {
+    next_id: u64,
+    order: LruOrder<StatementKey>,
+    map: HashMap<StatementKey, CachedStatement>,
+}
+
+impl StatementCache {
+    pub fn new(capacity: usize) -> StatementCache {
+        StatementCache {
+            next_id: 0,
+            order: LruOrder::new(capacity),
+            map: HashMap::new(),
+        }
+    }
+
+    pub fn get(&mut self, key: &StatementKey) -> Option<CachedStatement> {
+        self.order.touch(key);
+        self.map.get(key).cloned()
+    }
+
+    pub fn insert(&mut self, key: StatementKey, value: CachedStatement) {
+        if let Some(evicted) = self.order.insert(key.clone()) {
+            self.map.remove(&evicted);
+        }
+        self.map.insert(key, value);
+    }
+
+    pub fn remove(&mut self, key: &StatementKey) {
+        self.order.remove(key);
+        self.map.remove(key);
+    }
+
+    pub fn clear(&mut self) {
+        self.order.clear();
+        self.map.clear();
+    }
+
+    fn next_statement_name(&mut self) -> Bytes {
+        self.next_id += 1;
+        Bytes::from(format!("edgedb_rust_{}", self.next_id).into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod statement_cache_tests {
+    use super::LruOrder;
+
+    #[test]
+    fn evicts_oldest_when_over_capacity() {
+        let mut order = LruOrder::new(2);
+        assert_eq!(order.insert("a"), None);
+        assert_eq!(order.insert("b"), None);
+        assert_eq!(order.insert("c"), Some("a"));
+    }
+
+    #[test]
+    fn touch_protects_the_most_recently_used_entry() {
+        let mut order = LruOrder::new(2);
+        order.insert("a");
+        order.insert("b");
+        order.touch(&"a");
+        assert_eq!(order.insert("c"), Some("b"));
+    }
+
+    #[test]
+    fn reinserting_an_existing_key_does_not_evict() {
+        let mut order = LruOrder::new(2);
+        order.insert("a");
+        order.insert("b");
+        assert_eq!(order.insert("a"), None);
+        assert_eq!(order.insert("c"), Some("b"));
+    }
+
+    #[test]
+    fn zero_capacity_is_unbounded() {
+        let mut order = LruOrder::new(0);
+        assert_eq!(order.insert("a"), None);
+        assert_eq!(order.insert("b"), None);
+        assert_eq!(order.insert("c"), None);
+    }
+
+    #[test]
+    fn remove_drops_an_entry_from_the_order() {
+        let mut order = LruOrder::new(2);
+        order.insert("a");
+        order.insert("b");
+        order.remove(&"a");
+        assert_eq!(order.insert("c"), None);
+    }
+
+    #[test]
+    fn shrinking_capacity_evicts_down_to_the_new_limit() {
+        let mut order: LruOrder<&str> = LruOrder::new(3);
+        order.insert("a");
+        order.insert("b");
+        order.insert("c");
+        assert_eq!(order.set_capacity(1), vec!["a", "b"]);
+        assert_eq!(order.insert("d"), Some("c"));
+    }
+
+    #[test]
+    fn growing_capacity_evicts_nothing() {
+        let mut order: LruOrder<&str> = LruOrder::new(1);
+        order.insert("a");
+        assert_eq!(order.set_capacity(3), Vec::<&str>::new());
+        assert_eq!(order.insert("b"), None);
+    }
+}
+
+/// Abstracts the async runtime primitives the protocol implementation
+/// depends on, so `Connection` isn't hardwired to a single executor.
+///
+/// `AsyncStdRuntime` (behind the `runtime-async-std` feature, on by
+/// default) and `TokioRuntime` (behind `runtime-tokio`) are the two
+/// implementations shipped by this crate; `Connection<RT>` is generic
+/// over any `RT: Runtime`.
+pub trait Runtime: Send + Sync + 'static {
+    /// Read half of the split duplex connection.
+    type ReadHalf: AsyncRead + Unpin + Send + 'static;
+    /// Write half of the split duplex connection.
+    type WriteHalf: AsyncWrite + Unpin + Send + 'static;
+
+    /// Resolve after `duration` has elapsed.
+    fn sleep(duration: Duration) -> BoxFuture<'static, ()>;
+
+    /// Run `fut` to completion, failing with a
+    /// [`ClientConnectionTimeoutError`] if `duration` elapses first.
+    fn timeout<'f, T: Send + 'f>(duration: Duration, fut: BoxFuture<'f, T>)
+        -> BoxFuture<'f, Result<T, Error>>;
+}
+
+/// The default [`Runtime`]: drives the connection on the `async-std`
+/// executor and its timers.
+#[cfg(feature = "runtime-async-std")]
+#[derive(Debug)]
+pub struct AsyncStdRuntime;
+
+#[cfg(feature = "runtime-async-std")]
+impl Runtime for AsyncStdRuntime {
+    type ReadHalf = ReadHalf<TlsStream>;
+    type WriteHalf = WriteHalf<TlsStream>;
+
+    fn sleep(duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(async_std::task::sleep(duration))
+    }
+
+    fn timeout<'f, T: Send + 'f>(duration: Duration, fut: BoxFuture<'f, T>)
+        -> BoxFuture<'f, Result<T, Error>>
+    {
+        Box::pin(async move {
+            async_std::future::timeout(duration, fut).await
+                .map_err(ClientConnectionTimeoutError::with_source)
+        })
+    }
+}
+
+#[cfg(feature = "runtime-async-std")]
+pub type DefaultRuntime = AsyncStdRuntime;
+
+/// A [`Runtime`] that drives the connection on the `tokio` executor and
+/// its timers, generic over the underlying (already TLS-wrapped) duplex
+/// stream type `S`.
+#[cfg(feature = "runtime-tokio")]
+#[derive(Debug)]
+pub struct TokioRuntime<S>(std::marker::PhantomData<S>);
+
+#[cfg(feature = "runtime-tokio")]
+impl<S> Runtime for TokioRuntime<S>
+    where S: tokio::io::AsyncRead + tokio::io::AsyncWrite
+           + Unpin + Send + 'static,
+{
+    type ReadHalf = tokio_util::compat::Compat<tokio::io::ReadHalf<S>>;
+    type WriteHalf = tokio_util::compat::Compat<tokio::io::WriteHalf<S>>;
+
+    fn sleep(duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+
+    fn timeout<'f, T: Send + 'f>(duration: Duration, fut: BoxFuture<'f, T>)
+        -> BoxFuture<'f, Result<T, Error>>
+    {
+        Box::pin(async move {
+            tokio::time::timeout(duration, fut).await
+                .map_err(ClientConnectionTimeoutError::with_source)
+        })
+    }
+}
+
+#[cfg(all(feature = "runtime-tokio", not(feature = "runtime-async-std")))]
+pub type DefaultRuntime =
+    TokioRuntime<tokio_native_tls::TlsStream<tokio::net::TcpStream>>;
+
+
 #[derive(Debug)]
 /// A single connection to the EdgeDB
-pub struct Connection {
-    pub(crate) input: ReadHalf<TlsStream>,
-    pub(crate) output: WriteHalf<TlsStream>,
+pub struct Connection<RT: Runtime = DefaultRuntime> {
+    pub(crate) input: RT::ReadHalf,
+    pub(crate) output: RT::WriteHalf,
     pub(crate) input_buf: BytesMut,
     pub(crate) output_buf: BytesMut,
     pub(crate) version: ProtocolVersion,
     pub(crate) params: TypeMap<dyn typemap::DebugAny + Send + Sync>,
     pub(crate) transaction_state: TransactionState,
     pub(crate) dirty: bool,
+    pub(crate) stmt_cache: StatementCache,
 }
 
-pub struct Sequence<'a> {
-    pub writer: Writer<'a>,
-    pub reader: Reader<'a>,
+pub struct Sequence<'a, RT: Runtime = DefaultRuntime> {
+    pub writer: Writer<'a, RT>,
+    pub reader: Reader<'a, RT>,
     pub(crate) active: bool,
     pub(crate) dirty: &'a mut bool,
+    pub(crate) cache: &'a mut StatementCache,
 }
 
-pub struct Writer<'a> {
-    stream: &'a mut WriteHalf<TlsStream>,
+pub struct Writer<'a, RT: Runtime = DefaultRuntime> {
+    stream: &'a mut RT::WriteHalf,
     proto: &'a ProtocolVersion,
     outbuf: &'a mut BytesMut,
 }
@@ -88,10 +381,10 @@ impl StatementParams {
 }
 
 
-impl<'a> Sequence<'a> {
+impl<'a, RT: Runtime> Sequence<'a, RT> {
 
     pub fn response<T: QueryResult>(self, state: T::State)
-        -> QueryResponse<'a, T>
+        -> QueryResponse<'a, T, RT>
     {
         assert!(self.active);  // TODO(tailhook) maybe debug_assert
         reader::QueryResponse {
@@ -137,7 +430,7 @@ impl<'a> Sequence<'a> {
     }
 }
 
-impl Connection {
+impl<RT: Runtime> Connection<RT> {
     pub fn protocol(&self) -> &ProtocolVersion {
         return &self.version
     }
@@ -146,12 +439,32 @@ impl Connection {
         self.input.read(&mut buf[..]).await.ok();
         // any erroneous or successful read (even 0) means need reconnect
         self.dirty = true;
-        pending::<()>().await;
+        std::future::pending::<()>().await;
         unreachable!();
     }
     pub fn is_consistent(&self) -> bool {
         !self.dirty
     }
+    /// Drop all cached prepared statements.
+    ///
+    /// This must be called after running schema-changing DDL, since it
+    /// invalidates the output/input descriptors of any statement touching
+    /// the changed types.
+    pub fn clear_statement_cache(&mut self) {
+        self.stmt_cache.clear();
+    }
+    /// Set how many prepared statements are kept by the statement cache,
+    /// evicting the least-recently-used entries if the cache is currently
+    /// larger than `capacity`. `capacity == 0` means unbounded.
+    ///
+    /// There is no connection-builder equivalent of this; callers who want
+    /// a different size from the one the connection was created with must
+    /// call this once after connecting.
+    pub fn set_statement_cache_capacity(&mut self, capacity: usize) {
+        for evicted in self.stmt_cache.order.set_capacity(capacity) {
+            self.stmt_cache.map.remove(&evicted);
+        }
+    }
     pub async fn terminate(mut self) -> Result<(), Error> {
         let mut seq = self.start_sequence().await?;
         seq.send_messages(&[ClientMessage::Terminate]).await?;
@@ -163,7 +476,7 @@ impl Connection {
         }
     }
     pub async fn start_sequence<'x>(&'x mut self)
-        -> Result<Sequence<'x>, Error>
+        -> Result<Sequence<'x, RT>, Error>
     {
         if self.dirty {
             return Err(ClientInconsistentError::with_message(
@@ -175,6 +488,7 @@ impl Connection {
             buf: &mut self.input_buf,
             stream: &mut self.input,
             transaction_state: &mut self.transaction_state,
+            pending: None,
         };
         let writer = Writer {
             proto: &self.version,
@@ -186,6 +500,7 @@ impl Connection {
             reader,
             active: true,
             dirty: &mut self.dirty,
+            cache: &mut self.stmt_cache,
         })
     }
 
@@ -198,9 +513,274 @@ impl Connection {
     pub fn transaction_state(&self) -> TransactionState {
         self.transaction_state
     }
+
+    /// Execute a block of queries inside a retrying transaction.
+    ///
+    /// The `body` closure is invoked with a [`Transaction`] handle that can
+    /// be used to run `query`/`execute` calls against the open transaction.
+    /// `START TRANSACTION` is sent before each attempt and `COMMIT` after
+    /// the closure returns successfully. If the closure or the commit
+    /// fails with a transaction-serialization/deadlock error, the
+    /// transaction is rolled back and the whole closure is re-run from
+    /// scratch after an increasing backoff, up to
+    /// `DEFAULT_MAX_TRANSACTION_ATTEMPTS` (10) attempts in all. Network
+    /// errors are classified as retryable too, but such an error leaves
+    /// the connection marked inconsistent, so the `ROLLBACK` that precedes
+    /// a retry fails and its error is returned; the closure is not re-run.
+    /// Any other error is propagated immediately after rolling back.
+    pub async fn transaction<T, B, F>(&mut self, body: F)
+        -> Result<T, Error>
+        where B: Future<Output=Result<T, Error>>,
+              F: FnMut(Transaction<'_, RT>) -> B,
+    {
+        self.transaction_with_attempts(
+            DEFAULT_MAX_TRANSACTION_ATTEMPTS, body,
+        ).await
+    }
+
+    /// Same as [`Connection::transaction`] but with an explicit cap on the
+    /// number of attempts, instead of the default.
+    pub async fn transaction_with_attempts<T, B, F>(&mut self,
+        max_attempts: u32, mut body: F)
+        -> Result<T, Error>
+        where B: Future<Output=Result<T, Error>>,
+              F: FnMut(Transaction<'_, RT>) -> B,
+    {
+        let mut iteration = 0;
+        loop {
+            self.execute("START TRANSACTION").await?;
+            let result = body(Transaction { conn: self }).await;
+            let result = match result {
+                Ok(value) => match self.execute("COMMIT").await {
+                    Ok(_) => Ok(value),
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(value) => return Ok(value),
+                Err(e) => {
+                    if !is_retryable(&e) {
+                        // best-effort rollback, original error wins
+                        self.execute("ROLLBACK").await.ok();
+                        return Err(e);
+                    }
+                    if let Err(rollback_err) = self.execute("ROLLBACK").await {
+                        // `execute` already leaves `self.dirty` set when a
+                        // network error aborted the sequence early; there's
+                        // nothing else to mark here.
+                        return Err(rollback_err);
+                    }
+                    iteration += 1;
+                    if iteration >= max_attempts {
+                        return Err(e);
+                    }
+                    RT::sleep(retry_backoff(iteration)).await;
+                }
+            }
+        }
+    }
+}
+
+/// A handle to an open transaction, obtained inside the closure passed to
+/// [`Connection::transaction`].
+///
+/// All the usual query methods are available; they behave exactly like
+/// their `Connection` counterparts but run within the enclosing
+/// transaction.
+pub struct Transaction<'a, RT: Runtime = DefaultRuntime> {
+    conn: &'a mut Connection<RT>,
+}
+
+impl<'a, RT: Runtime> Transaction<'a, RT> {
+    pub async fn execute<S>(&mut self, request: S) -> Result<Bytes, Error>
+        where S: ToString,
+    {
+        self.conn.execute(request).await
+    }
+
+    pub async fn query<R, A>(&mut self, request: &str, arguments: &A)
+        -> Result<QueryResponse<'_, R, RT>, Error>
+        where R: QueryResult,
+              A: QueryArgs,
+    {
+        self.conn.query(request, arguments).await
+    }
+
+    pub async fn query_row<R, A>(&mut self, request: &str, arguments: &A)
+        -> Result<R, Error>
+        where R: Queryable,
+              A: QueryArgs,
+    {
+        self.conn.query_row(request, arguments).await
+    }
+
+    pub async fn query_row_opt<R, A>(&mut self, request: &str, arguments: &A)
+        -> Result<Option<R>, Error>
+        where R: Queryable,
+              A: QueryArgs,
+    {
+        self.conn.query_row_opt(request, arguments).await
+    }
+
+    pub async fn query_json<A>(&mut self, request: &str, arguments: &A)
+        -> Result<QueryResponse<'_, String, RT>, Error>
+        where A: QueryArgs,
+    {
+        self.conn.query_json(request, arguments).await
+    }
+
+    pub async fn query_json_els<A>(&mut self, request: &str, arguments: &A)
+        -> Result<QueryResponse<'_, String, RT>, Error>
+        where A: QueryArgs,
+    {
+        self.conn.query_json_els(request, arguments).await
+    }
+
+    pub async fn execute_stream<A, S, T, F>(&mut self, request: &str,
+        arguments: S, window: usize, on_row: F)
+        -> Result<usize, Error>
+        where A: QueryArgs,
+              S: futures_util::stream::Stream<Item=A> + Unpin,
+              T: QueryResult,
+              F: FnMut(T),
+    {
+        self.conn.execute_stream(request, arguments, window, on_row).await
+    }
+}
+
+fn is_network_error(e: &Error) -> bool {
+    e.is::<ClientConnectionError>() || e.is::<ClientConnectionEosError>()
 }
 
-impl<'a> Writer<'a> {
+/// Error code for `InvalidReferenceError`, which the server raises when a
+/// request references a prepared statement name it no longer recognizes
+/// (e.g. because the schema changed since it was prepared).
+const INVALID_REFERENCE_ERROR_CODE: u32 = 0x_03_01_04_01;
+
+/// Detects a server error reporting that a cached prepared-statement name
+/// is no longer valid, so `Sequence::_query` can evict it and re-prepare
+/// once instead of surfacing a confusing error to the caller.
+///
+/// This keys off the error's structured code, like `is_network_error`
+/// does, rather than pattern-matching its message text, so a wording
+/// change (or an unrelated error that happens to share some words) can't
+/// silently defeat the re-prepare path.
+fn is_stale_prepared_statement(err: &ErrorResponse) -> bool {
+    err.code == INVALID_REFERENCE_ERROR_CODE
+}
+
+fn is_retryable(e: &Error) -> bool {
+    e.is::<TransactionConflictError>() || is_network_error(e)
+}
+
+/// Exponential backoff plus a deterministic offset of up to three quarters
+/// of the base delay (varying with `iteration`, not random), capped at ten
+/// seconds, used between retried transaction attempts.
+fn retry_backoff(iteration: u32) -> Duration {
+    let base_ms = 10u64.saturating_mul(1u64 << iteration.min(10));
+    let jitter_ms = base_ms / 4 * (iteration as u64 % 4);
+    Duration::from_millis(base_ms + jitter_ms).min(Duration::from_secs(10))
+}
+
+#[cfg(test)]
+mod retry_tests {
+    use super::*;
+
+    #[test]
+    fn transaction_conflicts_and_network_errors_are_retryable() {
+        assert!(is_retryable(&TransactionConflictError::with_message("retry me")));
+        assert!(is_retryable(&ClientConnectionError::with_message("reset")));
+        assert!(is_retryable(&ClientConnectionEosError::with_message("eof")));
+    }
+
+    #[test]
+    fn other_errors_are_not_retryable() {
+        assert!(!is_retryable(&ProtocolError::with_message("bad frame")));
+        assert!(!is_retryable(&NoDataError::with_message("empty")));
+    }
+
+    #[test]
+    fn backoff_grows_with_iteration_and_is_capped() {
+        assert!(retry_backoff(0) < retry_backoff(1));
+        assert!(retry_backoff(1) < retry_backoff(4));
+        assert!(retry_backoff(100) <= Duration::from_secs(10));
+    }
+}
+
+/// Bounds the number of in-flight `Execute`s that
+/// [`Sequence::execute_stream`] keeps outstanding at once, so a bulk
+/// argument stream never floods the server with unacknowledged requests.
+struct WindowCounter {
+    window: usize,
+    outstanding: usize,
+}
+
+impl WindowCounter {
+    fn new(window: usize) -> WindowCounter {
+        WindowCounter { window: window.max(1), outstanding: 0 }
+    }
+
+    fn has_room(&self) -> bool {
+        self.outstanding < self.window
+    }
+
+    fn sent(&mut self) {
+        self.outstanding += 1;
+    }
+
+    fn acked(&mut self) {
+        self.outstanding -= 1;
+    }
+
+    fn is_drained(&self) -> bool {
+        self.outstanding == 0
+    }
+}
+
+#[cfg(test)]
+mod window_counter_tests {
+    use super::WindowCounter;
+
+    #[test]
+    fn has_room_until_window_is_full() {
+        let mut w = WindowCounter::new(2);
+        assert!(w.has_room());
+        w.sent();
+        assert!(w.has_room());
+        w.sent();
+        assert!(!w.has_room());
+    }
+
+    #[test]
+    fn acking_frees_up_room() {
+        let mut w = WindowCounter::new(1);
+        w.sent();
+        assert!(!w.has_room());
+        w.acked();
+        assert!(w.has_room());
+    }
+
+    #[test]
+    fn zero_window_is_clamped_to_one() {
+        let w = WindowCounter::new(0);
+        assert!(w.has_room());
+    }
+
+    #[test]
+    fn starts_drained_and_tracks_outstanding_count() {
+        let mut w = WindowCounter::new(4);
+        assert!(w.is_drained());
+        w.sent();
+        w.sent();
+        assert!(!w.is_drained());
+        w.acked();
+        w.acked();
+        assert!(w.is_drained());
+    }
+}
+
+impl<'a, RT: Runtime> Writer<'a, RT> {
 
     pub async fn send_messages<'x, I>(&mut self, msgs: I) -> Result<(), Error>
         where I: IntoIterator<Item=&'x ClientMessage>
@@ -220,7 +800,7 @@ impl<'a> Writer<'a> {
 }
 
 
-impl<'a> Sequence<'a> {
+impl<'a, RT: Runtime> Sequence<'a, RT> {
     pub async fn send_messages<'x, I>(&mut self, msgs: I)
         -> Result<(), Error>
         where I: IntoIterator<Item=&'x ClientMessage>
@@ -236,7 +816,7 @@ impl<'a> Sequence<'a> {
         Ok(())
     }
 
-    pub fn message(&mut self) -> reader::MessageFuture<'_, 'a> {
+    pub fn message(&mut self) -> reader::MessageFuture<'_, 'a, RT> {
         assert!(self.active);  // TODO(tailhook) maybe debug_assert
         self.reader.message()
     }
@@ -245,8 +825,8 @@ impl<'a> Sequence<'a> {
     pub async fn err_sync(&mut self) -> Result<(), Error> {
         assert!(self.active);  // TODO(tailhook) maybe debug_assert
         self.writer.send_messages(&[ClientMessage::Sync]).await?;
-        timeout(Duration::from_secs(10), self.expect_ready()).await
-            .map_err(ClientConnectionTimeoutError::with_source)??;
+        RT::timeout(Duration::from_secs(10), Box::pin(self.expect_ready()))
+            .await??;
         Ok(())
     }
 
@@ -280,7 +860,48 @@ impl<'a> Sequence<'a> {
         where A: QueryArgs + ?Sized,
     {
         assert!(self.active);  // TODO(tailhook) maybe debug_assert
-        let statement_name = Bytes::from_static(b"");
+        let (statement_name, output, input) = self._prepare(request, bld)
+            .await?;
+        self._execute_prepared(&statement_name, &input, arguments).await?;
+        self.send_messages(&[ClientMessage::Sync]).await?;
+
+        // Peek at the first response. If the statement we just executed
+        // turns out to have been a stale cache entry (e.g. invalidated by
+        // DDL since it was prepared), evict it and transparently
+        // re-prepare once; otherwise push the message back so whichever
+        // caller actually consumes the response sees it unchanged.
+        match self.reader.message().await? {
+            ServerMessage::ErrorResponse(err) if is_stale_prepared_statement(&err) =>
+            {
+                self.reader.wait_ready().await?;
+                self.evict_cached_statement(request, bld);
+                let (statement_name, output, input) =
+                    self._prepare(request, bld).await?;
+                self._execute_prepared(&statement_name, &input, arguments)
+                    .await?;
+                self.send_messages(&[ClientMessage::Sync]).await?;
+                Ok(output)
+            }
+            msg => {
+                self.reader.push_back(msg);
+                Ok(output)
+            }
+        }
+    }
+
+    /// Get the server-side statement name and the output/input descriptors
+    /// for `request`, preparing it (one `Prepare` + `DescribeStatement`
+    /// round-trip) and populating the cache on a miss, or reusing a cached
+    /// entry on a hit.
+    async fn _prepare(&mut self, request: &str, bld: &StatementParams)
+        -> Result<(Bytes, OutputTypedesc, InputTypedesc), Error>
+    {
+        let cache_key = (request.to_string(), bld.io_format, bld.cardinality);
+        if let Some(cached) = self.cache.get(&cache_key) {
+            return Ok((cached.name, cached.output, cached.input));
+        }
+
+        let statement_name = self.cache.next_statement_name();
 
         self.send_messages(&[
             ClientMessage::Prepare(Prepare {
@@ -335,30 +956,154 @@ impl<'a> Sequence<'a> {
                 }
             }
         };
-        let desc = data_description.output()
+        let output = data_description.output()
             .map_err(ProtocolEncodingError::with_source)?;
-        let inp_desc = data_description.input()
+        let input = data_description.input()
             .map_err(ProtocolEncodingError::with_source)?;
 
+        self.cache.insert(cache_key, CachedStatement {
+            name: statement_name.clone(),
+            output: output.clone(),
+            input: input.clone(),
+        });
+        Ok((statement_name, output, input))
+    }
+
+    /// Encode `arguments` against an already-prepared statement and send
+    /// `Execute`, skipping `Prepare`/`DescribeStatement` entirely. Callers
+    /// are responsible for following up with `Sync` or `Flush` once they
+    /// know whether more `Execute`s are coming.
+    async fn _execute_prepared<A>(&mut self, statement_name: &Bytes,
+        input: &InputTypedesc, arguments: &A)
+        -> Result<(), Error>
+        where A: QueryArgs + ?Sized,
+    {
         let mut arg_buf = BytesMut::with_capacity(8);
         arguments.encode(&mut Encoder::new(
-            &inp_desc.as_query_arg_context(),
+            &input.as_query_arg_context(),
             &mut arg_buf,
         ))?;
-
         self.send_messages(&[
             ClientMessage::Execute(Execute {
                 headers: HashMap::new(),
                 statement_name: statement_name.clone(),
                 arguments: arg_buf.freeze(),
             }),
-            ClientMessage::Sync,
         ]).await?;
-        Ok(desc)
+        Ok(())
+    }
+
+    /// Evict `request` from the statement cache.
+    ///
+    /// Called from the response-reading path when the server reports that
+    /// a cached statement name is unknown or expired (e.g. after DDL
+    /// changed the schema it was prepared against), so the next call for
+    /// the same query text transparently goes through `Prepare` again.
+    pub(crate) fn evict_cached_statement(&mut self,
+        request: &str, bld: &StatementParams)
+    {
+        let cache_key = (request.to_string(), bld.io_format, bld.cardinality);
+        self.cache.remove(&cache_key);
+    }
+
+    /// Execute `request` once per item produced by `arguments`, pipelining
+    /// the `Execute` messages so callers doing bulk loads don't pay a
+    /// round-trip per row, and decoding each row the statement returns
+    /// into `T` as it arrives via `on_row`.
+    ///
+    /// The statement is prepared only once — the same `Prepare` +
+    /// `DescribeStatement` round-trip (and cache entry) that a plain
+    /// `query` would use — and then reused for every item. At most
+    /// `window` `Execute`s are ever left un-acknowledged at a time, and
+    /// rows are handed to `on_row` as soon as they're decoded rather than
+    /// collected, so memory stays flat even when `arguments` yields
+    /// millions of rows. The first `ErrorResponse` aborts the sequence
+    /// and is returned; rows already handed to `on_row` before it are not
+    /// rolled back, matching how a single failing statement fails the
+    /// whole `execute`.
+    ///
+    /// Unlike a plain `query`, this does not transparently recover from a
+    /// stale cached statement (e.g. after DDL changed the schema since it
+    /// was prepared) — a bulk load started right after such a change
+    /// fails with the raw `ErrorResponse` instead of re-preparing once.
+    /// Call [`Connection::clear_statement_cache`] after DDL if you plan
+    /// to follow it with `execute_stream`.
+    pub(crate) async fn execute_stream<A, S, T, F>(&mut self, request: &str,
+        mut arguments: S, window: usize, mut on_row: F)
+        -> Result<usize, Error>
+        where A: QueryArgs,
+              S: futures_util::stream::Stream<Item=A> + Unpin,
+              T: QueryResult,
+              F: FnMut(T),
+    {
+        assert!(self.active);  // TODO(tailhook) maybe debug_assert
+        let mut window = WindowCounter::new(window);
+        let bld = StatementParams::new();
+        let (statement_name, output, input) =
+            self._prepare(request, &bld).await?;
+        let state = match output.root_pos() {
+            Some(root_pos) => {
+                let ctx = output.as_queryable_context();
+                Some(T::prepare(&ctx, root_pos)?)
+            }
+            None => None,
+        };
+
+        let mut count = 0usize;
+        let mut done_sending = false;
+        loop {
+            let mut sent_any = false;
+            while !done_sending && window.has_room() {
+                match arguments.next().await {
+                    Some(args) => {
+                        self._execute_prepared(&statement_name, &input,
+                            &args).await?;
+                        window.sent();
+                        sent_any = true;
+                    }
+                    None => {
+                        done_sending = true;
+                    }
+                }
+            }
+            if sent_any {
+                self.send_messages(&[ClientMessage::Flush]).await?;
+            }
+            if window.is_drained() {
+                break;
+            }
+            match self.reader.message().await? {
+                ServerMessage::Data(m) => {
+                    if let Some(state) = &state {
+                        for chunk in m.data {
+                            let item = T::decode(state, &chunk)
+                                .map_err(ProtocolEncodingError::with_source)?;
+                            on_row(item);
+                            count += 1;
+                        }
+                    }
+                }
+                ServerMessage::CommandComplete(_) => {
+                    window.acked();
+                }
+                ServerMessage::ErrorResponse(err) => {
+                    self.err_sync().await?;
+                    return Err(err.into());
+                }
+                msg => {
+                    return Err(ProtocolOutOfOrderError::with_message(format!(
+                        "Unsolicited message {:?}", msg)));
+                }
+            }
+        }
+
+        self.send_messages(&[ClientMessage::Sync]).await?;
+        self.expect_ready().await?;
+        Ok(count)
     }
 }
 
-impl Connection {
+impl<RT: Runtime> Connection<RT> {
     pub async fn execute<S>(&mut self, request: S)
         -> Result<Bytes, Error>
         where S: ToString,
@@ -389,7 +1134,7 @@ impl Connection {
     }
 
     pub async fn query<R, A>(&mut self, request: &str, arguments: &A)
-        -> Result<QueryResponse<'_, R>, Error>
+        -> Result<QueryResponse<'_, R, RT>, Error>
         where R: QueryResult,
               A: QueryArgs,
     {
@@ -451,7 +1196,7 @@ impl Connection {
     }
 
     pub async fn query_json<A>(&mut self, request: &str, arguments: &A)
-        -> Result<QueryResponse<'_, String>, Error>
+        -> Result<QueryResponse<'_, String, RT>, Error>
         where A: QueryArgs,
     {
         let mut seq = self.start_sequence().await?;
@@ -475,7 +1220,7 @@ impl Connection {
     }
 
     pub async fn query_json_els<A>(&mut self, request: &str, arguments: &A)
-        -> Result<QueryResponse<'_, String>, Error>
+        -> Result<QueryResponse<'_, String, RT>, Error>
         where A: QueryArgs,
     {
         let mut seq = self.start_sequence().await?;
@@ -508,10 +1253,194 @@ impl Connection {
         return seq._process_exec().await;
     }
 
+    /// Execute `request` once for every item produced by `arguments`,
+    /// pipelining the `Execute` messages instead of waiting for each one
+    /// to complete before sending the next, and calling `on_row` with
+    /// each decoded row as soon as it arrives. Returns the number of rows
+    /// decoded.
+    ///
+    /// `request` is prepared only once, regardless of how many items
+    /// `arguments` yields, making this the efficient path for bulk
+    /// inserts and other large argument sets. `window` bounds how many
+    /// `Execute`s may be outstanding and unacknowledged at once, and
+    /// rows are handed to `on_row` rather than collected, so memory use
+    /// stays flat even when `arguments` yields millions of rows; pass a
+    /// small constant (e.g. `DEFAULT_STREAM_WINDOW`) unless you've
+    /// measured that a larger window helps. On the first `ErrorResponse`
+    /// the whole call fails; rows already passed to `on_row` are not
+    /// rolled back, matching how a single failing statement fails the
+    /// whole `execute`.
+    ///
+    /// Unlike `query` and friends, this does not self-heal a stale cached
+    /// statement after schema-changing DDL — call
+    /// [`clear_statement_cache`](Connection::clear_statement_cache)
+    /// yourself first if the query might hit one.
+    pub async fn execute_stream<A, S, T, F>(&mut self, request: &str,
+        arguments: S, window: usize, on_row: F)
+        -> Result<usize, Error>
+        where A: QueryArgs,
+              S: futures_util::stream::Stream<Item=A> + Unpin,
+              T: QueryResult,
+              F: FnMut(T),
+    {
+        let mut seq = self.start_sequence().await?;
+        seq.execute_stream(request, arguments, window, on_row).await
+    }
+
     pub async fn get_version(&mut self) -> Result<String, Error> {
         self.query_row("SELECT sys::get_version_as_str()", &()).await
         .context("cannot fetch database version")
     }
 }
 
+#[cfg(feature = "runtime-async-std")]
+impl Connection<AsyncStdRuntime> {
+    /// Hand this connection off to a background task that owns the socket
+    /// directly, and get back a cheaply `Clone`-able [`ConnectionHandle`]
+    /// in return.
+    ///
+    /// Unlike a plain `Connection`, the handle can be shared between
+    /// tasks: each call submits its messages to the actor and gets its
+    /// own response stream back, so several queries can be pipelined back
+    /// to back on the wire without waiting for earlier ones to fully
+    /// drain.
+    ///
+    /// Only available for the `async-std` runtime for now: the actor is
+    /// spawned with `async_std::task::spawn`, which a `tokio`-backed
+    /// `Connection` can't use.
+    pub fn into_handle(self) -> ConnectionHandle {
+        let (tx, rx) = mpsc::unbounded();
+        async_std::task::spawn(connection_actor(self, rx));
+        ConnectionHandle { requests: tx }
+    }
+}
+
+/// One batch of client messages submitted to a [`ConnectionHandle`],
+/// together with the channel the actor should forward the matching
+/// server messages to.
+struct ActorRequest {
+    messages: Vec<ClientMessage>,
+    responses: mpsc::UnboundedSender<ServerMessage>,
+}
+
+/// A cheaply `Clone`-able handle to a [`Connection`] whose I/O is driven
+/// by a background task (see [`Connection::into_handle`]).
+///
+/// Several `submit` calls can be in flight at once: the actor writes each
+/// one to the socket as soon as it arrives and routes incoming server
+/// messages to the oldest still-unfinished request, so callers don't have
+/// to wait for each other to finish reading their response before the
+/// next request can be written.
+#[derive(Clone)]
+pub struct ConnectionHandle {
+    requests: mpsc::UnboundedSender<ActorRequest>,
+}
+
+impl ConnectionHandle {
+    /// Submit a batch of client messages and get back a stream of the
+    /// server messages belonging to this request. The stream ends once
+    /// the matching `ReadyForCommand` has been read (it is delivered as
+    /// the last item), or early if the connection's socket errors.
+    pub async fn submit(&self, messages: Vec<ClientMessage>)
+        -> Result<mpsc::UnboundedReceiver<ServerMessage>, Error>
+    {
+        let (tx, rx) = mpsc::unbounded();
+        self.requests.unbounded_send(ActorRequest { messages, responses: tx })
+            .map_err(|_| ClientConnectionEosError::with_message(
+                "connection actor is no longer running"))?;
+        Ok(rx)
+    }
+}
+
+/// Body of the background task spawned by [`Connection::into_handle`].
+///
+/// Splits into a writer half, which forwards each incoming
+/// `ActorRequest`'s messages to the socket and enqueues its response
+/// channel, and a reader half, which routes every incoming server message
+/// to the oldest enqueued request until that request's `ReadyForCommand`
+/// goes by. The two halves only share the FIFO of response channels, so
+/// a new request can be written while an older one is still being read.
+#[cfg(feature = "runtime-async-std")]
+async fn connection_actor(conn: Connection<AsyncStdRuntime>,
+    mut requests: mpsc::UnboundedReceiver<ActorRequest>)
+{
+    let Connection {
+        input, output, input_buf, output_buf, version, mut transaction_state,
+        ..
+    } = conn;
+    let version = Arc::new(version);
+    let in_flight = Arc::new(
+        Mutex::new(VecDeque::<mpsc::UnboundedSender<ServerMessage>>::new()));
+    let closed = Arc::new(AtomicBool::new(false));
+
+    let write_version = version.clone();
+    let write_queue = in_flight.clone();
+    let write_closed = closed.clone();
+    let writer = async move {
+        let mut output = output;
+        let mut output_buf = output_buf;
+        while let Some(req) = requests.next().await {
+            let mut writer = Writer {
+                proto: &*write_version,
+                outbuf: &mut output_buf,
+                stream: &mut output,
+            };
+            match writer.send_messages(&req.messages).await {
+                Ok(()) => {
+                    let mut queue = write_queue.lock().await;
+                    if write_closed.load(Ordering::SeqCst) {
+                        // The reader has already given up on the socket:
+                        // dropping the response channel ends the stream.
+                        break;
+                    }
+                    queue.push_back(req.responses);
+                }
+                Err(_) => {
+                    // The response channel is simply dropped: the caller
+                    // sees the stream end with no `ReadyForCommand`, which
+                    // is indistinguishable from any other connection loss.
+                    break;
+                }
+            }
+        }
+    };
+
+    let read_version = version;
+    let read_queue = in_flight;
+    let reader = async move {
+        let mut input = input;
+        let mut input_buf = input_buf;
+        loop {
+            let msg = {
+                let mut reader = Reader {
+                    proto: &*read_version,
+                    buf: &mut input_buf,
+                    stream: &mut input,
+                    transaction_state: &mut transaction_state,
+                    pending: None,
+                };
+                reader.message().await
+            };
+            let msg = match msg {
+                Ok(msg) => msg,
+                Err(_) => break,
+            };
+            let is_ready = matches!(msg, ServerMessage::ReadyForCommand(_));
+            let mut queue = read_queue.lock().await;
+            if let Some(sender) = if is_ready {
+                queue.pop_front()
+            } else {
+                queue.front().cloned()
+            } {
+                sender.unbounded_send(msg).ok();
+            }
+        }
+        // The socket is gone: fail every request still waiting on us by
+        // dropping its response channel.
+        let mut queue = read_queue.lock().await;
+        closed.store(true, Ordering::SeqCst);
+        queue.clear();
+    };
 
+    futures_util::future::join(writer, reader).await;
+}
@@ -0,0 +1,179 @@
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::{Buf, Bytes, BytesMut};
+use futures_util::future::BoxFuture;
+use futures_util::io::AsyncReadExt;
+
+use edgedb_protocol::features::ProtocolVersion;
+use edgedb_protocol::server_message::{ServerMessage, ReadyForCommand};
+use edgedb_protocol::server_message::TransactionState;
+use edgedb_protocol::QueryResult;
+
+use crate::client::{Runtime, Sequence};
+use crate::errors::{Error, ErrorKind};
+use crate::errors::{ClientConnectionError, ClientConnectionEosError};
+use crate::errors::{ProtocolEncodingError, ProtocolOutOfOrderError};
+
+
+/// Buffered reader over the server-to-client half of a connection.
+///
+/// Holds a borrow of the transport's read half (generic over the
+/// [`Runtime`] in use) plus the shared protocol version and
+/// transaction-state slots, so a [`Sequence`](crate::client::Sequence)
+/// can read messages without owning the connection outright.
+pub struct Reader<'a, RT: Runtime> {
+    pub(crate) proto: &'a ProtocolVersion,
+    pub(crate) buf: &'a mut BytesMut,
+    pub(crate) stream: &'a mut RT::ReadHalf,
+    pub(crate) transaction_state: &'a mut TransactionState,
+    pub(crate) pending: Option<ServerMessage>,
+}
+
+impl<'a, RT: Runtime> Reader<'a, RT> {
+    pub fn message(&mut self) -> MessageFuture<'_, 'a, RT> {
+        MessageFuture(Box::pin(self.read_message()), PhantomData)
+    }
+
+    /// Put `message` back so the next call to [`Reader::message`] returns
+    /// it instead of reading from the socket. Used to peek at a single
+    /// response (e.g. to check for a stale prepared statement) without
+    /// stealing it from whichever caller actually expects to consume it.
+    pub(crate) fn push_back(&mut self, message: ServerMessage) {
+        debug_assert!(self.pending.is_none());
+        self.pending = Some(message);
+    }
+
+    pub async fn wait_ready(&mut self) -> Result<(), Error> {
+        loop {
+            match self.message().await? {
+                ServerMessage::ReadyForCommand(ready) => {
+                    self.consume_ready(ready);
+                    return Ok(());
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    pub fn consume_ready(&mut self, ready: ReadyForCommand) {
+        *self.transaction_state = ready.transaction_state;
+    }
+
+    async fn read_message(&mut self) -> Result<ServerMessage, Error> {
+        if let Some(message) = self.pending.take() {
+            return Ok(message);
+        }
+        loop {
+            if let Some(message) = self.try_decode()? {
+                return Ok(message);
+            }
+            let mut chunk = [0u8; 8192];
+            let n = self.stream.read(&mut chunk).await
+                .map_err(ClientConnectionError::with_source)?;
+            if n == 0 {
+                return Err(ClientConnectionEosError::with_message(
+                    "connection closed by the server"));
+            }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    fn try_decode(&mut self) -> Result<Option<ServerMessage>, Error> {
+        // mtype (1 byte) + length (4 bytes, big-endian, includes itself
+        // but not mtype)
+        if self.buf.len() < 5 {
+            return Ok(None);
+        }
+        let len = (&self.buf[1..5]).get_u32() as usize;
+        if self.buf.len() < 1 + len {
+            return Ok(None);
+        }
+        let frame = self.buf.split_to(1 + len).freeze();
+        let message = ServerMessage::decode(&frame, self.proto)
+            .map_err(ProtocolEncodingError::with_source)?;
+        Ok(Some(message))
+    }
+}
+
+/// Future returned by [`Reader::message`]. Named so it can appear in
+/// [`Sequence::message`](crate::client::Sequence::message)'s signature.
+pub struct MessageFuture<'s, 'a, RT: Runtime>(
+    BoxFuture<'s, Result<ServerMessage, Error>>,
+    PhantomData<&'s Reader<'a, RT>>,
+);
+
+impl<'s, 'a, RT: Runtime> Future for MessageFuture<'s, 'a, RT> {
+    type Output = Result<ServerMessage, Error>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.0.as_mut().poll(cx)
+    }
+}
+
+/// A lazy, pull-based stream of decoded rows for a single query.
+///
+/// Bytes already read off the wire but not yet decoded into `T` are kept
+/// in `buffer`; `next` pulls more `Data`/`CommandComplete`/`ErrorResponse`
+/// messages off the [`Sequence`] as needed.
+pub struct QueryResponse<'a, T: QueryResult, RT: Runtime> {
+    pub(crate) seq: Sequence<'a, RT>,
+    pub(crate) buffer: Vec<Bytes>,
+    pub(crate) error: Option<Error>,
+    pub(crate) complete: bool,
+    pub(crate) state: T::State,
+}
+
+impl<'a, T: QueryResult, RT: Runtime> QueryResponse<'a, T, RT> {
+    pub async fn next(&mut self) -> Option<Result<T, Error>> {
+        if let Some(err) = self.error.take() {
+            self.complete = true;
+            return Some(Err(err));
+        }
+        loop {
+            if !self.buffer.is_empty() {
+                let data = self.buffer.remove(0);
+                return Some(T::decode(&self.state, &data)
+                    .map_err(ProtocolEncodingError::with_source));
+            }
+            if self.complete {
+                return None;
+            }
+            match self.seq.message().await {
+                Ok(ServerMessage::Data(m)) => {
+                    self.buffer.extend(m.data);
+                }
+                Ok(ServerMessage::CommandComplete(_)) => {
+                    self.complete = true;
+                    if let Err(e) = self.seq.expect_ready().await {
+                        return Some(Err(e));
+                    }
+                }
+                Ok(ServerMessage::ErrorResponse(e)) => {
+                    self.complete = true;
+                    self.seq.expect_ready().await.ok();
+                    return Some(Err(e.into()));
+                }
+                Ok(msg) => {
+                    self.complete = true;
+                    return Some(Err(ProtocolOutOfOrderError::with_message(
+                        format!("unsolicited message {:?}", msg))));
+                }
+                Err(e) => {
+                    self.complete = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+
+    /// Drain and discard any rows not yet consumed by the caller, so the
+    /// underlying [`Sequence`] ends up back in a clean, reusable state.
+    pub async fn skip_remaining(&mut self) -> Result<(), Error> {
+        while let Some(item) = self.next().await {
+            item?;
+        }
+        Ok(())
+    }
+}